@@ -1,16 +1,19 @@
 mod request;
 mod response;
 
+use async_trait::async_trait;
 use clap::Clap;
 use rand::{Rng, SeedableRng};
+use redis::AsyncCommands;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 use std::sync::Arc;
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, HashSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::io::{Error, ErrorKind};
 use tokio::time::delay_for;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::convert::TryInto;
 use http::request::*;
 
@@ -46,6 +49,408 @@ struct CmdOptions {
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "How many requests worth of burst an IP may send ahead of the steady rate before being throttled",
+        default_value = "1"
+    )]
+    rate_limit_burst_size: usize,
+    #[clap(
+        long,
+        about = "Maximum number of idle keep-alive connections to hold open per upstream",
+        default_value = "16"
+    )]
+    max_idle_per_upstream: usize,
+    #[clap(
+        long,
+        about = "How long (in seconds) an idle pooled upstream connection may sit before we discard it instead of reusing it",
+        default_value = "60"
+    )]
+    idle_connection_timeout: usize,
+    #[clap(
+        long,
+        about = "Load-balancing strategy: one of \"random\", \"round-robin\", \"least-connections\", \"consistent-hash\"",
+        default_value = "random"
+    )]
+    lb_strategy: String,
+    #[clap(
+        long,
+        about = "Number of virtual-node replicas per upstream on the consistent-hash ring",
+        default_value = "100"
+    )]
+    consistent_hash_replicas: usize,
+    #[clap(
+        long,
+        about = "Failure count within the sliding window that trips an upstream's circuit breaker open",
+        default_value = "5"
+    )]
+    circuit_breaker_failure_threshold: usize,
+    #[clap(
+        long,
+        about = "Sliding window (in seconds) over which circuit-breaker failures are counted",
+        default_value = "10"
+    )]
+    circuit_breaker_window: usize,
+    #[clap(
+        long,
+        about = "How long (in seconds) a tripped circuit breaker stays open before allowing a half-open probe",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown: usize,
+    #[clap(
+        long,
+        about = "Redis URL (e.g. redis://127.0.0.1:6379) backing rate limiting and dead-upstream state across a fleet of instances. Falls back to the in-memory path when unset."
+    )]
+    redis_url: Option<String>,
+    #[clap(
+        long,
+        about = "Add or overwrite a request header before forwarding, as \"Name: Value\" (repeatable)"
+    )]
+    add_request_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Remove a request header before forwarding (repeatable)"
+    )]
+    remove_request_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Add or overwrite a response header before returning it to the client, as \"Name: Value\" (repeatable)"
+    )]
+    add_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Remove a response header before returning it to the client (repeatable)"
+    )]
+    remove_response_header: Vec<String>,
+    #[clap(
+        long,
+        about = "Reject requests whose body is larger than this many bytes"
+    )]
+    max_request_body_size: Option<usize>,
+    #[clap(
+        long,
+        about = "Reject requests whose Content-Type doesn't start with one of these prefixes (repeatable)"
+    )]
+    allowed_content_type: Vec<String>,
+    #[clap(
+        long,
+        about = "Maximum number of concurrent client connections to accept (unbounded if unset)"
+    )]
+    max_connections: Option<usize>,
+    #[clap(
+        long,
+        about = "How long (in seconds) to wait for in-flight connections to drain during graceful shutdown",
+        default_value = "30"
+    )]
+    graceful_shutdown_timeout: usize,
+}
+
+/// Connection-permit capacity used when `--max-connections` is unset, large enough to never
+/// meaningfully throttle accepts while still giving graceful shutdown something to drain against
+/// (Milestone 11).
+const UNBOUNDED_CONNECTIONS: usize = 1 << 20;
+
+/// A single accept-concurrency permit, checked out of a bounded `mpsc` channel pre-loaded with
+/// `capacity` unit tokens. Tokio's `Semaphore` doesn't expose an owned permit that can be held
+/// across a `tokio::spawn`ed task on the tokio release this crate is pinned to, so a bounded
+/// channel stands in for one instead: "acquire" is `rx.recv()` on the shared receiver, and
+/// dropping a permit returns its token to the pool so a queued acquirer can proceed.
+struct ConnectionPermit {
+    release_tx: mpsc::Sender<()>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let _ = self.release_tx.try_send(());
+    }
+}
+
+/// Blocks until a connection permit is available, then checks it out.
+async fn acquire_connection_permit(
+    permit_rx: &mut mpsc::Receiver<()>,
+    release_tx: &mpsc::Sender<()>,
+) -> ConnectionPermit {
+    permit_rx
+        .recv()
+        .await
+        .expect("connection permit channel closed while a sender is still held");
+    ConnectionPermit {
+        release_tx: release_tx.clone(),
+    }
+}
+
+/// Which policy `connect_to_upstream` uses to pick an upstream for each client request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LbStrategy {
+    /// Uniform random choice among live upstreams (the original behavior).
+    Random,
+    /// Cycles through live upstreams in order.
+    RoundRobin,
+    /// Picks whichever live upstream currently has the fewest in-flight requests.
+    LeastConnections,
+    /// Hashes the client's IP onto a ring of virtual nodes so the same client keeps landing on
+    /// the same upstream (useful for cache locality / session stickiness), and only a fraction
+    /// of clients remap when an upstream goes up or down.
+    ConsistentHash,
+}
+
+impl LbStrategy {
+    fn parse(s: &str) -> Option<LbStrategy> {
+        match s {
+            "random" => Some(LbStrategy::Random),
+            "round-robin" | "round_robin" => Some(LbStrategy::RoundRobin),
+            "least-connections" | "least_connections" => Some(LbStrategy::LeastConnections),
+            "consistent-hash" | "consistent_hash" => Some(LbStrategy::ConsistentHash),
+            _ => None,
+        }
+    }
+}
+
+/// Hashes a ring key (either `"{addr}#{replica}"` when building the ring, or a client's sticky
+/// key when routing) onto `u64` space.
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Builds the consistent-hash ring: each upstream gets `replicas` virtual nodes scattered
+/// around the ring so load spreads evenly and only ~1/N of keys remap when an upstream is
+/// added or removed.
+fn build_hash_ring(upstream_addresses: &[String], replicas: usize) -> BTreeMap<u64, String> {
+    let mut ring = BTreeMap::new();
+    for addr in upstream_addresses {
+        for replica in 0..replicas {
+            ring.insert(hash_key(&format!("{}#{}", addr, replica)), addr.clone());
+        }
+    }
+    ring
+}
+
+/// A per-upstream circuit breaker's state. `Closed` is normal operation; `Open` ejects the
+/// upstream for a cooldown once it's been failing; `HalfOpen` lets a probe request back in to
+/// test whether the upstream has recovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Tracks an upstream's health from live request/response outcomes (connection resets, I/O
+/// errors, 5xx responses) observed in `handle_connection`, complementing the active health
+/// checker by reacting within the same request instead of waiting for the next poll.
+struct CircuitBreaker {
+    state: BreakerState,
+    /// Timestamps of failures observed while `Closed`, oldest first, pruned to the sliding
+    /// window on every failure.
+    recent_failures: VecDeque<Instant>,
+    opened_at: Option<Instant>,
+    /// Set while a `HalfOpen` breaker has already handed its single probe request to some
+    /// connection, so concurrent callers don't all pile onto the recovering upstream at once.
+    /// Cleared by `record_success`/`record_failure` once that probe's outcome is known.
+    probe_in_flight: bool,
+}
+
+impl CircuitBreaker {
+    fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            recent_failures: VecDeque::new(),
+            opened_at: None,
+            probe_in_flight: false,
+        }
+    }
+
+    /// Moves an `Open` breaker to `HalfOpen` once its cooldown has elapsed.
+    fn tick(&mut self, now: Instant, cooldown: Duration) {
+        if self.state == BreakerState::Open {
+            if let Some(opened_at) = self.opened_at {
+                if now.duration_since(opened_at) >= cooldown {
+                    self.state = BreakerState::HalfOpen;
+                    self.probe_in_flight = false;
+                }
+            }
+        }
+    }
+
+    /// Whether a connection may be routed to this upstream right now: always true while
+    /// `Closed`, false while `Open`, and true for `HalfOpen` only if no other connection has
+    /// already claimed the single probe slot.
+    fn is_admissible(&self) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => !self.probe_in_flight,
+            BreakerState::Open => false,
+        }
+    }
+
+    /// Claims the `HalfOpen` probe slot for the connection that was just routed here, so
+    /// concurrent callers stop treating this upstream as admissible until the probe's outcome is
+    /// recorded. A no-op outside `HalfOpen`.
+    fn claim_probe(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            self.probe_in_flight = true;
+        }
+    }
+
+    /// A request to this upstream succeeded. In `HalfOpen`, that's enough to promote back to
+    /// `Closed`; otherwise there's nothing to do.
+    fn record_success(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            self.state = BreakerState::Closed;
+            self.recent_failures.clear();
+            self.opened_at = None;
+            self.probe_in_flight = false;
+        }
+    }
+
+    /// A request to this upstream failed. In `HalfOpen`, that re-opens the breaker for another
+    /// cooldown; in `Closed`, it's recorded in the sliding window and trips the breaker open if
+    /// `threshold` failures land within `window`.
+    fn record_failure(&mut self, now: Instant, window: Duration, threshold: usize) {
+        match self.state {
+            BreakerState::HalfOpen => {
+                self.state = BreakerState::Open;
+                self.opened_at = Some(now);
+                self.recent_failures.clear();
+                self.probe_in_flight = false;
+            }
+            BreakerState::Open => {}
+            BreakerState::Closed => {
+                self.recent_failures.push_back(now);
+                while let Some(&oldest) = self.recent_failures.front() {
+                    if now.duration_since(oldest) > window {
+                        self.recent_failures.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                if self.recent_failures.len() >= threshold {
+                    self.state = BreakerState::Open;
+                    self.opened_at = Some(now);
+                }
+            }
+        }
+    }
+}
+
+/// A single stage in the request/response pipeline, run against every client request and the
+/// response that comes back for it. Modules run in registration order for `on_request` (so an
+/// earlier module's rewrite is visible to a later one) and can short-circuit the pipeline by
+/// returning a response instead of letting the request reach the upstream (Milestone 10).
+#[async_trait]
+trait Filter: Send + Sync {
+    /// Runs after `x-forwarded-for` is added and before the request is forwarded upstream.
+    /// Returning `Some(response)` sends that response straight to the client instead.
+    async fn on_request(
+        &self,
+        _request: &mut http::Request<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        None
+    }
+
+    /// Runs on the upstream's response before it's forwarded to the client.
+    async fn on_response(&self, _response: &mut http::Response<Vec<u8>>) {}
+}
+
+/// Adds, removes, or rewrites a fixed set of request/response headers, configured via CLI
+/// (Milestone 10).
+struct HeaderFilter {
+    add_request_headers: Vec<(String, String)>,
+    remove_request_headers: Vec<String>,
+    add_response_headers: Vec<(String, String)>,
+    remove_response_headers: Vec<String>,
+}
+
+#[async_trait]
+impl Filter for HeaderFilter {
+    async fn on_request(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        for name in &self.remove_request_headers {
+            request.headers_mut().remove(name.as_str());
+        }
+        for (name, value) in &self.add_request_headers {
+            request.headers_mut().insert(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                http::HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        None
+    }
+
+    async fn on_response(&self, response: &mut http::Response<Vec<u8>>) {
+        for name in &self.remove_response_headers {
+            response.headers_mut().remove(name.as_str());
+        }
+        for (name, value) in &self.add_response_headers {
+            response.headers_mut().insert(
+                http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                http::HeaderValue::from_str(value).unwrap(),
+            );
+        }
+    }
+}
+
+/// Rejects requests whose body exceeds a configured size or whose `Content-Type` isn't in a
+/// configured allow-list, configured via CLI (Milestone 10).
+struct BodyGuardFilter {
+    max_body_size: Option<usize>,
+    allowed_content_types: Option<Vec<String>>,
+}
+
+#[async_trait]
+impl Filter for BodyGuardFilter {
+    async fn on_request(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+    ) -> Option<http::Response<Vec<u8>>> {
+        if let Some(max) = self.max_body_size {
+            if request.body().len() > max {
+                return Some(response::make_http_error(http::StatusCode::PAYLOAD_TOO_LARGE));
+            }
+        }
+        if let Some(allowed) = &self.allowed_content_types {
+            let content_type = request
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            if !allowed.iter().any(|prefix| content_type.starts_with(prefix.as_str())) {
+                return Some(response::make_http_error(http::StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+        }
+        None
+    }
+}
+
+/// Parses repeatable `"Name: Value"` CLI arguments (as used by `--add-request-header` and
+/// `--add-response-header`) into `(name, value)` pairs, validating that each side can actually
+/// build an `http::HeaderName`/`HeaderValue` so a bad operator-supplied value fails fast at
+/// startup instead of panicking `HeaderFilter::on_request`/`on_response` on the first live request.
+fn parse_header_pairs(raw: &[String]) -> Vec<(String, String)> {
+    raw.iter()
+        .map(|entry| match entry.split_once(':') {
+            Some((name, value)) => {
+                let (name, value) = (name.trim(), value.trim());
+                if let Err(e) = http::HeaderName::from_bytes(name.as_bytes()) {
+                    log::error!("Invalid header name \"{}\": {}", name, e);
+                    std::process::exit(1);
+                }
+                if let Err(e) = http::HeaderValue::from_str(value) {
+                    log::error!("Invalid header value \"{}\": {}", value, e);
+                    std::process::exit(1);
+                }
+                (name.to_string(), value.to_string())
+            }
+            None => {
+                log::error!("Invalid header \"{}\"; expected \"Name: Value\".", entry);
+                std::process::exit(1);
+            }
+        })
+        .collect()
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -62,13 +467,71 @@ struct ProxyState {
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
+    /// How many requests worth of burst an IP may send ahead of the steady rate (Milestone 5)
+    #[allow(dead_code)]
+    rate_limit_burst_size: usize,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Vec<String>,
     /// Addresses of dead servers
     dead_upstreams: HashSet<String>,
-    /// Number of requests by each client IP
-    num_reqs_by_ip: HashMap<String, usize>,
+    /// Per-IP GCRA state: the "theoretical arrival time" (TAT) of each client's next allowed
+    /// request. Smoothly limits each IP to `max_requests_per_minute` with bursts bounded by
+    /// `rate_limit_burst_size`, without the reset spikes of a fixed window (Milestone 5). Since
+    /// client IP is attacker-controlled, `prune_rate_limiter_task` periodically drops entries
+    /// whose TAT has been stale for a while so this can't grow without bound.
+    rate_limiter: HashMap<String, Instant>,
+    /// Idle keep-alive connections to upstreams, available for reuse by the next client that
+    /// targets the same upstream (Milestone 6)
+    upstream_pool: HashMap<String, Vec<PooledConnection>>,
+    /// Maximum number of idle connections to keep pooled per upstream (Milestone 6)
+    max_idle_per_upstream: usize,
+    /// How long a pooled connection may sit idle before we discard it instead of reusing it
+    /// (Milestone 6)
+    idle_connection_timeout: Duration,
+    /// How client requests are assigned to upstreams (Milestone 7)
+    lb_strategy: LbStrategy,
+    /// Cursor used by `LbStrategy::RoundRobin` to cycle through live upstreams (Milestone 7)
+    round_robin_counter: usize,
+    /// Number of in-flight requests currently assigned to each upstream, used by
+    /// `LbStrategy::LeastConnections` (Milestone 7)
+    in_flight: HashMap<String, usize>,
+    /// Consistent-hash ring used by `LbStrategy::ConsistentHash`, mapping virtual-node hashes to
+    /// the upstream address that owns them (Milestone 7)
+    hash_ring: BTreeMap<u64, String>,
+    /// Per-upstream circuit breakers driven by live request/response outcomes (Milestone 8)
+    breakers: HashMap<String, CircuitBreaker>,
+    /// Failure count within `circuit_breaker_window` that trips a breaker open
+    /// (Milestone 8)
+    circuit_breaker_failure_threshold: usize,
+    /// Sliding window over which circuit-breaker failures are counted (Milestone 8)
+    circuit_breaker_window: Duration,
+    /// How long a tripped breaker stays open before allowing a half-open probe (Milestone 8)
+    circuit_breaker_cooldown: Duration,
+    /// Redis client backing rate limiting and dead-upstream state across a fleet of balancebeam
+    /// instances proxying the same upstreams; `None` falls back to the in-memory GCRA limiter
+    /// and this instance's own `dead_upstreams` set (Milestone 9)
+    redis_client: Option<redis::Client>,
+    /// A persistent, cloneable connection to `redis_client`'s server, reused across every rate-limit
+    /// check and health-status publish instead of opening a fresh TCP connection per request.
+    /// `redis::aio::MultiplexedConnection` is designed to be cloned and driven concurrently from
+    /// many tasks, so cloning it out of `ProxyState` is cheap. `None` iff `redis_client` is.
+    redis_conn: Option<redis::aio::MultiplexedConnection>,
+    /// Request/response pipeline modules, run in order against every request and response
+    /// (Milestone 10)
+    filters: Vec<Box<dyn Filter>>,
+    /// The release side of the accept loop's connection-permit channel: bounds concurrent client
+    /// connections so a flood can't exhaust memory/FDs, with the accept loop checking out a
+    /// `ConnectionPermit` before spawning a handler and releasing it (via `Drop`) when the
+    /// handler finishes. Sized to `--max-connections`, or `UNBOUNDED_CONNECTIONS` when that's
+    /// unset (Milestone 11)
+    connection_permit_tx: mpsc::Sender<()>,
+}
 
+/// An upstream `TcpStream` sitting in the pool, along with when it became idle so we can expire
+/// it instead of handing back a connection the upstream may have already closed.
+struct PooledConnection {
+    stream: TcpStream,
+    idle_since: Instant,
 }
 
 #[tokio::main]
@@ -88,6 +551,75 @@ async fn main() {
         log::error!("At least one upstream server must be specified using the --upstream option.");
         std::process::exit(1);
     }
+    let lb_strategy = match LbStrategy::parse(&options.lb_strategy) {
+        Some(strategy) => strategy,
+        None => {
+            log::error!(
+                "Unrecognized --lb-strategy \"{}\"; expected one of random, round-robin, least-connections, consistent-hash.",
+                options.lb_strategy
+            );
+            std::process::exit(1);
+        }
+    };
+    let hash_ring = build_hash_ring(&options.upstream, options.consistent_hash_replicas);
+    let redis_client = match &options.redis_url {
+        Some(url) => match redis::Client::open(url.as_str()) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                log::error!("Invalid --redis-url \"{}\": {}", url, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // Open one persistent, cloneable connection up front instead of dialing Redis fresh on every
+    // rate-limit check or health-status publish.
+    let redis_conn = match &redis_client {
+        Some(client) => match client.get_multiplexed_tokio_connection().await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                log::error!("Could not connect to --redis-url: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let connection_capacity = options.max_connections.unwrap_or(UNBOUNDED_CONNECTIONS);
+    let (connection_permit_tx, mut connection_permit_rx) = mpsc::channel::<()>(connection_capacity);
+    for _ in 0..connection_capacity {
+        connection_permit_tx
+            .clone()
+            .send(())
+            .await
+            .expect("connection permit channel closed during startup");
+    }
+
+    // Build the filter pipeline from whichever built-in modules the CLI options configure.
+    let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+    let add_request_headers = parse_header_pairs(&options.add_request_header);
+    let add_response_headers = parse_header_pairs(&options.add_response_header);
+    if !add_request_headers.is_empty()
+        || !options.remove_request_header.is_empty()
+        || !add_response_headers.is_empty()
+        || !options.remove_response_header.is_empty()
+    {
+        filters.push(Box::new(HeaderFilter {
+            add_request_headers,
+            remove_request_headers: options.remove_request_header.clone(),
+            add_response_headers,
+            remove_response_headers: options.remove_response_header.clone(),
+        }));
+    }
+    if options.max_request_body_size.is_some() || !options.allowed_content_type.is_empty() {
+        filters.push(Box::new(BodyGuardFilter {
+            max_body_size: options.max_request_body_size,
+            allowed_content_types: if options.allowed_content_type.is_empty() {
+                None
+            } else {
+                Some(options.allowed_content_type.clone())
+            },
+        }));
+    }
 
     // Start listening for connections
     let mut listener = TcpListener::bind(&options.bind).await.unwrap();
@@ -101,8 +633,24 @@ async fn main() {
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
         max_requests_per_minute: options.max_requests_per_minute,
+        rate_limit_burst_size: options.rate_limit_burst_size,
         dead_upstreams: HashSet::new(),
-        num_reqs_by_ip: HashMap::new(),
+        rate_limiter: HashMap::new(),
+        upstream_pool: HashMap::new(),
+        max_idle_per_upstream: options.max_idle_per_upstream,
+        idle_connection_timeout: Duration::from_secs(options.idle_connection_timeout as u64),
+        lb_strategy,
+        round_robin_counter: 0,
+        in_flight: HashMap::new(),
+        hash_ring,
+        breakers: HashMap::new(),
+        circuit_breaker_failure_threshold: options.circuit_breaker_failure_threshold,
+        circuit_breaker_window: Duration::from_secs(options.circuit_breaker_window as u64),
+        circuit_breaker_cooldown: Duration::from_secs(options.circuit_breaker_cooldown as u64),
+        redis_client: redis_client.clone(),
+        redis_conn: redis_conn.clone(),
+        filters,
+        connection_permit_tx: connection_permit_tx.clone(),
     }));
 
     // Spawn active health checker.
@@ -114,67 +662,472 @@ async fn main() {
                 });
     }
 
-    // Spawn rate-limiting monitor that empties the counts every minute.
+    // Periodically prune stale `rate_limiter` entries so a flood of distinct client IPs can't
+    // grow it without bound.
     {
         let state = state.clone();
         tokio::spawn(async move {
-                    loop {
-                        delay_for(Duration::from_millis(60000)).await;
-                        state.write().await.num_reqs_by_ip.clear();
-                    }
-                });
+            log::info!("Spawned rate-limiter pruner.");
+            prune_rate_limiter_task(state).await;
+        });
     }
-    
 
-    // Handle incoming connections
-    while let Some(stream) = incoming.next().await {
+    // Subscribe to dead/alive transitions published by other balancebeam instances sharing this
+    // Redis, so a failure one of them detects shows up in our own `dead_upstreams` too.
+    if let Some(client) = redis_client {
+        let state = state.clone();
+        tokio::spawn(async move {
+            log::info!("Spawned Redis upstream-health subscriber.");
+            subscribe_to_upstream_health(client, state).await;
+        });
+    }
+
+    // Handle incoming connections, bounded by the connection-permit channel so a flood of
+    // clients can't exhaust memory/FDs: we don't call `incoming.next()` again until a permit
+    // frees up, rather than spawning a handler per connection unboundedly. SIGINT/SIGTERM stop
+    // accepting and drain in-flight connections instead of cutting them off mid-request.
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let permit = tokio::select! {
+            permit = acquire_connection_permit(&mut connection_permit_rx, &connection_permit_tx) => permit,
+            _ = &mut shutdown => break,
+        };
+        let stream = tokio::select! {
+            stream = incoming.next() => stream,
+            _ = &mut shutdown => break,
+        };
         match stream {
-            Ok(stream) => {
+            Some(Ok(stream)) => {
                 let state = state.clone();
                 tokio::spawn(async move {
                     log::debug!("New connection!");
                     handle_connection(stream, state).await;
+                    drop(permit);
                 });
             }
-            Err(_e) => log::error!("Failed to accept a connection.")
+            Some(Err(_e)) => {
+                log::error!("Failed to accept a connection.");
+                drop(permit);
+            }
+            None => {
+                drop(permit);
+                break;
+            }
+        }
+    }
+
+    log::info!("Shutting down: draining in-flight connections...");
+    drain_connections(
+        &mut connection_permit_rx,
+        connection_capacity,
+        Duration::from_secs(options.graceful_shutdown_timeout as u64),
+    )
+    .await;
+    state.write().await.upstream_pool.clear();
+    log::info!("Closed pooled upstream connections; shutdown complete.");
+}
+
+/// Resolves once SIGINT or SIGTERM is received, so the accept loop can stop taking new
+/// connections and start a graceful drain (Milestone 11).
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = ctrl_c => log::info!("Received SIGINT."),
+        _ = sigterm.recv() => log::info!("Received SIGTERM."),
+    }
+}
+
+/// Waits for every in-flight connection to return its permit (`capacity` tokens back in the
+/// channel) or `timeout` to elapse, whichever comes first, so shutdown doesn't cut off requests
+/// that are still mid-flight (Milestone 11).
+async fn drain_connections(permit_rx: &mut mpsc::Receiver<()>, capacity: usize, timeout: Duration) {
+    let wait_for_all_idle = async {
+        for _ in 0..capacity {
+            permit_rx
+                .recv()
+                .await
+                .expect("connection permit channel closed while a sender is still held");
         }
-        
+    };
+    if tokio::time::timeout(timeout, wait_for_all_idle).await.is_err() {
+        log::warn!("Graceful shutdown timed out with connections still in flight; closing anyway.");
     }
 }
 
-async fn connect_to_upstream(lock: Arc<RwLock<ProxyState>>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
-    let mut upstream_idx;
-    let mut upstream_ip;
-    {
-        let state = lock.read().await;
-        let valid_upstreams: Vec<String> = state.upstream_addresses.iter().cloned()
-            .filter(|x| !state.dead_upstreams.contains(x)).collect();
-        if valid_upstreams.len() == 0 {
-            return Err(Error::new(ErrorKind::Other, "All upstream servers are unreachable."));
+/// Checks whether a pooled connection still looks usable by peeking for a closed socket.
+/// Idle keep-alive connections normally have nothing waiting to be read, so a peek that times
+/// out immediately (no data ready) is treated as alive; a peek that immediately reports EOF or
+/// an error means the upstream has already hung up.
+async fn is_connection_alive(stream: &mut TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    match tokio::time::timeout(Duration::from_millis(0), stream.peek(&mut buf)).await {
+        Ok(Ok(0)) => false,
+        Ok(Ok(_)) => true,
+        Ok(Err(_)) => false,
+        Err(_) => true,
+    }
+}
+
+/// Pops a pooled connection to `addr`, discarding (and retrying) any that have sat idle past
+/// the configured timeout or no longer look alive. Returns `None` once the pool for `addr` is
+/// empty.
+async fn checkout_connection(lock: &Arc<RwLock<ProxyState>>, addr: &str) -> Option<TcpStream> {
+    let idle_timeout = lock.read().await.idle_connection_timeout;
+    loop {
+        let mut pooled = {
+            let mut state = lock.write().await;
+            state.upstream_pool.get_mut(addr)?.pop()
+        }?;
+        if pooled.idle_since.elapsed() > idle_timeout {
+            log::debug!("Discarding pooled connection to {}: idle too long.", addr);
+            continue;
+        }
+        if is_connection_alive(&mut pooled.stream).await {
+            return Some(pooled.stream);
         }
-        upstream_idx = rng.gen_range(0, valid_upstreams.len());
-        upstream_ip = valid_upstreams[upstream_idx].clone();
+        log::debug!("Discarding pooled connection to {}: looks half-closed.", addr);
     }
+}
+
+/// Returns an idle keep-alive connection to the pool for `addr` to be reused by the next client
+/// that targets the same upstream, unless the pool for that upstream is already at capacity.
+async fn checkin_connection(lock: &Arc<RwLock<ProxyState>>, addr: String, stream: TcpStream) {
+    let mut state = lock.write().await;
+    let max_idle = state.max_idle_per_upstream;
+    let conns = state.upstream_pool.entry(addr.clone()).or_insert_with(Vec::new);
+    if conns.len() < max_idle {
+        conns.push(PooledConnection {
+            stream,
+            idle_since: Instant::now(),
+        });
+    } else {
+        log::debug!("Idle pool for {} is full; closing connection instead of pooling it.", addr);
+    }
+}
+
+/// Picks a live upstream according to `state.lb_strategy`. `sticky_key` (the client's IP) is
+/// only consulted by `LbStrategy::ConsistentHash`.
+async fn pick_upstream_address(
+    lock: &Arc<RwLock<ProxyState>>,
+    sticky_key: &str,
+) -> Result<String, std::io::Error> {
+    let mut state = lock.write().await;
+
+    // Let any breakers whose cooldown has elapsed move from Open to HalfOpen before we decide
+    // which upstreams are eligible.
+    let now = Instant::now();
+    let cooldown = state.circuit_breaker_cooldown;
+    for breaker in state.breakers.values_mut() {
+        breaker.tick(now, cooldown);
+    }
+
+    let valid_upstreams: Vec<String> = state
+        .upstream_addresses
+        .iter()
+        .cloned()
+        .filter(|x| !state.dead_upstreams.contains(x))
+        .filter(|x| {
+            state
+                .breakers
+                .get(x)
+                .map(|b| b.is_admissible())
+                .unwrap_or(true)
+        })
+        .collect();
+    if valid_upstreams.len() == 0 {
+        return Err(Error::new(ErrorKind::Other, "All upstream servers are unreachable."));
+    }
+    let addr = match state.lb_strategy {
+        LbStrategy::Random => {
+            let mut rng = rand::rngs::StdRng::from_entropy();
+            valid_upstreams[rng.gen_range(0, valid_upstreams.len())].clone()
+        }
+        LbStrategy::RoundRobin => {
+            let idx = state.round_robin_counter % valid_upstreams.len();
+            state.round_robin_counter = state.round_robin_counter.wrapping_add(1);
+            valid_upstreams[idx].clone()
+        }
+        LbStrategy::LeastConnections => valid_upstreams
+            .iter()
+            .min_by_key(|addr| state.in_flight.get(addr.as_str()).copied().unwrap_or(0))
+            .unwrap()
+            .clone(),
+        LbStrategy::ConsistentHash => {
+            let eligible: HashSet<&str> = valid_upstreams.iter().map(String::as_str).collect();
+            let hash = hash_key(sticky_key);
+            state
+                .hash_ring
+                .range(hash..)
+                .chain(state.hash_ring.iter())
+                .map(|(_, addr)| addr)
+                .find(|addr| eligible.contains(addr.as_str()))
+                .cloned()
+                .unwrap_or_else(|| valid_upstreams[0].clone())
+        }
+    };
+    // Claim the HalfOpen probe slot (if any) for the upstream we actually routed to, so
+    // concurrent callers don't also treat it as admissible before this request's outcome lands.
+    if let Some(breaker) = state.breakers.get_mut(&addr) {
+        breaker.claim_probe();
+    }
+    Ok(addr)
+}
+
+async fn connect_to_upstream(
+    lock: Arc<RwLock<ProxyState>>,
+    sticky_key: &str,
+) -> Result<(TcpStream, String), std::io::Error> {
+    let mut upstream_ip = pick_upstream_address(&lock, sticky_key).await?;
     loop {
+        if let Some(stream) = checkout_connection(&lock, &upstream_ip).await {
+            log::debug!("Reusing pooled connection to {}", upstream_ip);
+            return Ok((stream, upstream_ip));
+        }
         match TcpStream::connect(&upstream_ip).await {
-            Ok(stream) => return Ok(stream),
+            Ok(stream) => return Ok((stream, upstream_ip)),
             Err(_e) => {
                 log::warn!("Upstream server {} is unreachable. Trying another.", upstream_ip);
-                let mut state = lock.write().await;
-                state.dead_upstreams.insert(upstream_ip.clone());
-                let valid_upstreams: Vec<String> = state.upstream_addresses.iter().cloned()
-                    .filter(|x| !state.dead_upstreams.contains(x)).collect();
-                if valid_upstreams.len() == 0 {
-                    return Err(Error::new(ErrorKind::Other, "All upstream servers are unreachable."));
-                }
-                upstream_idx = rng.gen_range(0, valid_upstreams.len());
-                upstream_ip = valid_upstreams[upstream_idx].clone();
+                mark_upstream_dead(&lock, &upstream_ip).await;
+                // Also feed this into the circuit breaker so a claimed HalfOpen probe slot
+                // doesn't stay stuck forever when the probe never even reaches a live
+                // connection to report success or failure from inside handle_connection.
+                record_upstream_failure(&lock, &upstream_ip).await;
+                upstream_ip = pick_upstream_address(&lock, sticky_key).await?;
             }
         }
     }
 }
 
+/// Tracks that a client request has been routed to `addr`, for `LbStrategy::LeastConnections`.
+async fn increment_in_flight(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let mut state = lock.write().await;
+    *state.in_flight.entry(addr.to_string()).or_insert(0) += 1;
+}
+
+/// Releases the in-flight slot claimed by `increment_in_flight` once that request's round-trip
+/// with the upstream has finished or failed.
+async fn decrement_in_flight(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let mut state = lock.write().await;
+    if let Some(count) = state.in_flight.get_mut(addr) {
+        *count = count.saturating_sub(1);
+    }
+}
+
+/// Feeds a connection reset, I/O error, or 5xx response from `addr` into its circuit breaker.
+async fn record_upstream_failure(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let mut state = lock.write().await;
+    let now = Instant::now();
+    let window = state.circuit_breaker_window;
+    let threshold = state.circuit_breaker_failure_threshold;
+    let breaker = state
+        .breakers
+        .entry(addr.to_string())
+        .or_insert_with(CircuitBreaker::new);
+    let was_open = breaker.state == BreakerState::Open;
+    breaker.record_failure(now, window, threshold);
+    if !was_open && breaker.state == BreakerState::Open {
+        log::warn!("Circuit breaker for {} tripped open after repeated failures.", addr);
+    }
+}
+
+/// Feeds a successful request/response round-trip with `addr` into its circuit breaker.
+async fn record_upstream_success(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let mut state = lock.write().await;
+    if let Some(breaker) = state.breakers.get_mut(addr) {
+        breaker.record_success();
+    }
+}
+
+/// A Generic Cell Rate Algorithm limiter: tracks each client's "theoretical arrival time" (TAT)
+/// instead of a count, so there's no window boundary to burst across and no global reset spike.
+/// Given a rate of `max_requests_per_minute`, the steady emission interval is `T = 60s / N`, and
+/// up to `rate_limit_burst_size` requests' worth of slack (`tau = T * burst_size`) is tolerated
+/// ahead of that steady rate. Returns `Err(retry_after)` if the request should be rejected.
+fn check_rate_limit(state: &mut ProxyState, client_ip: &str) -> Result<(), Duration> {
+    if state.max_requests_per_minute == 0 {
+        return Ok(());
+    }
+    let emission_interval = Duration::from_secs_f64(60.0 / state.max_requests_per_minute as f64);
+    let burst_tolerance = emission_interval * state.rate_limit_burst_size as u32;
+
+    let now = Instant::now();
+    let mut tat = state.rate_limiter.get(client_ip).copied().unwrap_or(now);
+    if tat < now {
+        tat = now;
+    }
+    if tat.duration_since(now) > burst_tolerance {
+        return Err(tat.duration_since(now) - burst_tolerance);
+    }
+    state.rate_limiter.insert(client_ip.to_string(), tat + emission_interval);
+    Ok(())
+}
+
+/// How often `prune_rate_limiter_task` sweeps `rate_limiter` for stale entries.
+const RATE_LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Drops `rate_limiter` entries whose TAT fell behind `now` by more than that client's own burst
+/// tolerance, i.e. clients who haven't sent a request in a while and have no budget left to
+/// protect. Without this, `rate_limiter` grows by one entry per distinct client IP for the life
+/// of the process, and since client IP is attacker-controlled that's an easy unbounded-memory
+/// target on an internet-facing proxy.
+fn prune_rate_limiter(state: &mut ProxyState) {
+    if state.max_requests_per_minute == 0 {
+        return;
+    }
+    let emission_interval = Duration::from_secs_f64(60.0 / state.max_requests_per_minute as f64);
+    let burst_tolerance = emission_interval * state.rate_limit_burst_size as u32;
+    let now = Instant::now();
+    state.rate_limiter.retain(|_, tat| {
+        now.checked_duration_since(*tat)
+            .map(|idle| idle <= burst_tolerance)
+            .unwrap_or(true)
+    });
+}
+
+/// Periodically sweeps `state.rate_limiter` via `prune_rate_limiter` so it can't grow without
+/// bound (Milestone 5).
+async fn prune_rate_limiter_task(lock: Arc<RwLock<ProxyState>>) {
+    loop {
+        delay_for(RATE_LIMITER_PRUNE_INTERVAL).await;
+        let mut state = lock.write().await;
+        prune_rate_limiter(&mut state);
+    }
+}
+
+/// Channel dead/alive transitions are published to so every balancebeam instance sharing the
+/// same Redis sees the same `dead_upstreams` set (Milestone 9).
+const UPSTREAM_HEALTH_CHANNEL: &str = "balancebeam:upstream-health";
+
+/// The same GCRA math as `check_rate_limit`, run atomically server-side in Redis so a client's
+/// budget is shared across every balancebeam instance behind the same Redis instead of being
+/// tracked per-process (Milestone 9). KEYS[1] is the client's TAT key; ARGV is
+/// `(now_ms, emission_interval_ms, burst_tolerance_ms, key_ttl_ms)`. Returns `{allowed, retry_after_ms}`.
+const GCRA_SCRIPT: &str = r#"
+local tat = tonumber(redis.call('GET', KEYS[1]))
+local now = tonumber(ARGV[1])
+local emission_interval = tonumber(ARGV[2])
+local burst_tolerance = tonumber(ARGV[3])
+local ttl = tonumber(ARGV[4])
+if tat == nil or tat < now then
+    tat = now
+end
+if tat - now > burst_tolerance then
+    return {0, tat - now - burst_tolerance}
+end
+tat = tat + emission_interval
+redis.call('SET', KEYS[1], tat, 'PX', ttl)
+return {1, 0}
+"#;
+
+/// Runs the GCRA rate-limit check against Redis instead of `state.rate_limiter`, so the limit is
+/// shared across every balancebeam instance pointed at the same Redis (Milestone 9). Fails open
+/// (allows the request) if Redis is unreachable or the script errors.
+async fn check_rate_limit_redis(
+    conn: &mut redis::aio::MultiplexedConnection,
+    max_requests_per_minute: usize,
+    burst_size: usize,
+    client_ip: &str,
+) -> Result<(), Duration> {
+    if max_requests_per_minute == 0 {
+        return Ok(());
+    }
+    let emission_interval = Duration::from_secs_f64(60.0 / max_requests_per_minute as f64);
+    let burst_tolerance = emission_interval * burst_size as u32;
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let ttl_ms = (burst_tolerance + emission_interval).as_millis() as i64 + 1000;
+
+    let result: Result<(i64, i64), redis::RedisError> = redis::Script::new(GCRA_SCRIPT)
+        .key(format!("balancebeam:rate-limit:{}", client_ip))
+        .arg(now_ms)
+        .arg(emission_interval.as_millis() as i64)
+        .arg(burst_tolerance.as_millis() as i64)
+        .arg(ttl_ms)
+        .invoke_async(conn)
+        .await;
+    match result {
+        Ok((1, _)) => Ok(()),
+        Ok((_, retry_after_ms)) => Err(Duration::from_millis(retry_after_ms.max(0) as u64)),
+        Err(e) => {
+            log::warn!("Redis rate-limit script failed ({}); allowing request.", e);
+            Ok(())
+        }
+    }
+}
+
+/// Marks `addr` dead locally and, if Redis is configured, publishes the transition so every other
+/// balancebeam instance sharing it reacts without waiting for its own health checks (Milestone 9).
+async fn mark_upstream_dead(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let redis_conn = {
+        let mut state = lock.write().await;
+        state.dead_upstreams.insert(addr.to_string());
+        state.redis_conn.clone()
+    };
+    if let Some(mut conn) = redis_conn {
+        publish_upstream_health(&mut conn, addr, false).await;
+    }
+}
+
+/// Marks `addr` alive locally and, if Redis is configured, publishes the transition (Milestone 9).
+async fn mark_upstream_alive(lock: &Arc<RwLock<ProxyState>>, addr: &str) {
+    let redis_conn = {
+        let mut state = lock.write().await;
+        state.dead_upstreams.remove(addr);
+        state.redis_conn.clone()
+    };
+    if let Some(mut conn) = redis_conn {
+        publish_upstream_health(&mut conn, addr, true).await;
+    }
+}
+
+async fn publish_upstream_health(conn: &mut redis::aio::MultiplexedConnection, addr: &str, alive: bool) {
+    let payload = format!("{}:{}", if alive { "alive" } else { "dead" }, addr);
+    if let Err(e) = conn.publish::<_, _, ()>(UPSTREAM_HEALTH_CHANNEL, payload).await {
+        log::warn!("Failed to publish upstream-health event for {}: {}", addr, e);
+    }
+}
+
+/// Listens for dead/alive transitions published by other balancebeam instances and applies them
+/// to our own `dead_upstreams`, so a failure another instance detects is reflected here without
+/// waiting for our own active or passive health checks to notice (Milestone 9).
+async fn subscribe_to_upstream_health(client: redis::Client, lock: Arc<RwLock<ProxyState>>) {
+    loop {
+        let conn = match client.get_async_connection().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::warn!("Redis health subscriber could not connect ({}); retrying.", e);
+                delay_for(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let mut pubsub = conn.into_pubsub();
+        if let Err(e) = pubsub.subscribe(UPSTREAM_HEALTH_CHANNEL).await {
+            log::warn!("Redis health subscriber failed to subscribe ({}); retrying.", e);
+            delay_for(Duration::from_secs(1)).await;
+            continue;
+        }
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let payload: String = match msg.get_payload() {
+                Ok(payload) => payload,
+                Err(_) => continue,
+            };
+            if let Some(addr) = payload.strip_prefix("dead:") {
+                lock.write().await.dead_upstreams.insert(addr.to_string());
+            } else if let Some(addr) = payload.strip_prefix("alive:") {
+                lock.write().await.dead_upstreams.remove(addr);
+            }
+        }
+        log::warn!("Redis health subscriber connection dropped; reconnecting.");
+        delay_for(Duration::from_secs(1)).await;
+    }
+}
+
 async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
     let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
     log::info!("{} <- {}", client_ip, response::format_response_line(&response));
@@ -188,30 +1141,16 @@ async fn handle_connection(mut client_conn: TcpStream, lock: Arc<RwLock<ProxySta
     let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
     log::info!("Connection received from {}", client_ip);
 
-    // Open a connection to a random destination server
-    let mut upstream_conn;
-    {
-        let lock = lock.clone();
-        upstream_conn = match connect_to_upstream(lock).await {
-            Ok(stream) => stream,
-            Err(_error) => {
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
-                return;
-            }
-        };
-    }
-    
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
-
     // The client may now send us one or more requests. Keep trying to read requests until the
-    // client hangs up or we get an error.
+    // client hangs up or we get an error. No upstream connection is held between requests: each
+    // request checks one out of the pool (or dials a fresh one) and checks it back in right after
+    // its response comes back, so a client's idle think-time between requests doesn't pin an
+    // upstream socket that another concurrent client could be reusing.
     loop {
-        
         // Read the request.
         let mut request = match request::read_from_stream(&mut client_conn).await {
             Ok(request) => request,
-            // Handle case where client closed connection and is no longer sending requests
+            // Handle case where client closed connection and is no longer sending requests.
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
@@ -236,27 +1175,37 @@ async fn handle_connection(mut client_conn: TcpStream, lock: Arc<RwLock<ProxySta
             }
         };
         log::info!(
-            "{} -> {}: {}",
+            "{} <- request: {}",
             client_ip,
-            upstream_ip,
             request::format_request_line(&request)
         );
 
-        // If rate limit exceeded, send error to client.
-        {   
-            let mut state = lock.write().await;
-            let max_reqs = state.max_requests_per_minute.clone();
-            if let None = state.num_reqs_by_ip.get_mut(&client_ip) {
-                state.num_reqs_by_ip.insert(client_ip.clone(), 0);
-            } 
-            let num = state.num_reqs_by_ip.get_mut(&client_ip).unwrap(); 
-            *num += 1;
-            log::debug!("reqs: {}; max reqs: {}", num, &max_reqs);
-            if *num > max_reqs && max_reqs > 0 {
-                let resp = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                send_response(&mut client_conn, &resp).await;
-                continue;
+        // If rate limit exceeded, send error to client (with a Retry-After hint). When Redis is
+        // configured the limit is enforced there instead, so every balancebeam instance sharing
+        // it agrees on each client's remaining budget.
+        let redis_conn = { lock.read().await.redis_conn.clone() };
+        let rate_limit_result = match redis_conn {
+            Some(mut conn) => {
+                let (max_rpm, burst) = {
+                    let state = lock.read().await;
+                    (state.max_requests_per_minute, state.rate_limit_burst_size)
+                };
+                check_rate_limit_redis(&mut conn, max_rpm, burst, &client_ip).await
+            }
+            None => {
+                let mut state = lock.write().await;
+                check_rate_limit(&mut state, &client_ip)
             }
+        };
+        if let Err(retry_after) = rate_limit_result {
+            log::debug!("Rate limit exceeded for {}; retry after {:?}", client_ip, retry_after);
+            let mut resp = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            resp.headers_mut().insert(
+                "Retry-After",
+                http::HeaderValue::from_str(&retry_after.as_secs().max(1).to_string()).unwrap(),
+            );
+            send_response(&mut client_conn, &resp).await;
+            continue;
         }
 
         // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
@@ -264,9 +1213,46 @@ async fn handle_connection(mut client_conn: TcpStream, lock: Arc<RwLock<ProxySta
         // upstream server will only know our IP, not the client's.)
         request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
 
+        // Run the request through the filter pipeline: modules can rewrite headers in place or
+        // short-circuit the response entirely (e.g. the body-size/content-type guard).
+        let short_circuit = {
+            let state = lock.read().await;
+            let mut short_circuit = None;
+            for filter in &state.filters {
+                if let Some(response) = filter.on_request(&mut request).await {
+                    short_circuit = Some(response);
+                    break;
+                }
+            }
+            short_circuit
+        };
+        if let Some(response) = short_circuit {
+            send_response(&mut client_conn, &response).await;
+            continue;
+        }
+
+        // Check out an upstream connection for this one request, reusing a pooled keep-alive
+        // connection when one's available instead of always paying for a fresh TCP handshake.
+        // Chosen fresh per request (rather than once per client connection, as before) and
+        // checked back in right after this request's response comes back, so a multi-request
+        // keep-alive client doesn't pin one upstream socket for its whole lifetime.
+        let (mut upstream_conn, upstream_addr) = match connect_to_upstream(lock.clone(), &client_ip).await {
+            Ok((stream, addr)) => (stream, addr),
+            Err(_error) => {
+                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                send_response(&mut client_conn, &response).await;
+                return;
+            }
+        };
+        let upstream_ip = upstream_addr.clone();
+        increment_in_flight(&lock, &upstream_addr).await;
+        log::debug!("Routing {} -> {}", client_ip, upstream_ip);
+
         // Forward the request to the server
         if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
             log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
+            decrement_in_flight(&lock, &upstream_addr).await;
+            record_upstream_failure(&lock, &upstream_addr).await;
             let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
             send_response(&mut client_conn, &response).await;
             return;
@@ -274,15 +1260,35 @@ async fn handle_connection(mut client_conn: TcpStream, lock: Arc<RwLock<ProxySta
         log::debug!("Forwarded request to server");
 
         // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
+        let mut response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
             Ok(response) => response,
             Err(error) => {
                 log::error!("Error reading response from server: {:?}", error);
+                decrement_in_flight(&lock, &upstream_addr).await;
+                record_upstream_failure(&lock, &upstream_addr).await;
                 let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
                 send_response(&mut client_conn, &response).await;
                 return;
             }
         };
+        // A 5xx from the upstream is a failure from the breaker's point of view even though the
+        // round-trip itself succeeded; anything else counts as a success.
+        if response.status().is_server_error() {
+            record_upstream_failure(&lock, &upstream_addr).await;
+        } else {
+            record_upstream_success(&lock, &upstream_addr).await;
+        }
+        decrement_in_flight(&lock, &upstream_addr).await;
+        // The round-trip finished cleanly, so hand the upstream connection straight back to the
+        // pool for the next request (ours or another client's) instead of holding it for the rest
+        // of this keep-alive client connection.
+        checkin_connection(&lock, upstream_addr, upstream_conn).await;
+        {
+            let state = lock.read().await;
+            for filter in &state.filters {
+                filter.on_response(&mut response).await;
+            }
+        }
         // Forward the response to the client
         send_response(&mut client_conn, &response).await;
         log::debug!("Forwarded response to client");
@@ -316,25 +1322,21 @@ async fn active_health_checker(lock: Arc<RwLock<ProxyState>>) {
                                 let resp = response::read_from_stream(&mut stream, &http::Method::GET).await.unwrap();
                                 if resp.status().as_u16() == 200 {
                                     log::debug!("Upstream {} ok.", u);
-                                    let mut state = lock.write().await;
-                                    state.dead_upstreams.remove(&u);
+                                    mark_upstream_alive(&lock, &u).await;
                                 } else {
                                     log::warn!("Received non-200 status from {}.", u);
-                                    let mut state = lock.write().await;
-                                    state.dead_upstreams.insert(u.clone());
+                                    mark_upstream_dead(&lock, &u).await;
                                 }
                             }
                             Err(_e) => {
                                 log::warn!("Error sending request to {}.", u);
-                                let mut state = lock.write().await;
-                                state.dead_upstreams.insert(u.clone());
+                                mark_upstream_dead(&lock, &u).await;
                             }
                         }
                     }
                     Err(_e) => {
                         log::warn!("Upstream server {} is unreachable.", u);
-                        let mut state = lock.write().await;
-                        state.dead_upstreams.insert(u.clone());
+                        mark_upstream_dead(&lock, &u).await;
                     }
                 }
             });
@@ -352,3 +1354,179 @@ fn build_request(path: &String, host: &String) -> Request<Vec<u8>> {
         .unwrap()
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A `ProxyState` with every knob at an inert default; tests override just the fields their
+    /// scenario cares about.
+    fn test_state(max_requests_per_minute: usize, rate_limit_burst_size: usize) -> ProxyState {
+        let (connection_permit_tx, _connection_permit_rx) = mpsc::channel::<()>(1);
+        ProxyState {
+            active_health_check_interval: 0,
+            active_health_check_path: String::new(),
+            max_requests_per_minute,
+            rate_limit_burst_size,
+            upstream_addresses: Vec::new(),
+            dead_upstreams: HashSet::new(),
+            rate_limiter: HashMap::new(),
+            upstream_pool: HashMap::new(),
+            max_idle_per_upstream: 0,
+            idle_connection_timeout: Duration::from_secs(0),
+            lb_strategy: LbStrategy::Random,
+            round_robin_counter: 0,
+            in_flight: HashMap::new(),
+            hash_ring: BTreeMap::new(),
+            breakers: HashMap::new(),
+            circuit_breaker_failure_threshold: 0,
+            circuit_breaker_window: Duration::from_secs(0),
+            circuit_breaker_cooldown: Duration::from_secs(0),
+            redis_client: None,
+            redis_conn: None,
+            filters: Vec::new(),
+            connection_permit_tx,
+        }
+    }
+
+    #[test]
+    fn test_check_rate_limit_allows_up_to_the_burst() {
+        let mut state = test_state(60, 2);
+        // 1 request/sec steady rate with a burst of 2: the first 3 requests (1 steady + 2 burst)
+        // should be admitted back-to-back.
+        assert!(check_rate_limit(&mut state, "1.2.3.4").is_ok());
+        assert!(check_rate_limit(&mut state, "1.2.3.4").is_ok());
+        assert!(check_rate_limit(&mut state, "1.2.3.4").is_ok());
+    }
+
+    #[test]
+    fn test_check_rate_limit_rejects_once_burst_is_exhausted() {
+        let mut state = test_state(60, 0);
+        assert!(check_rate_limit(&mut state, "1.2.3.4").is_ok());
+        // No burst tolerance, so the very next request (sent immediately) is over budget.
+        assert!(check_rate_limit(&mut state, "1.2.3.4").is_err());
+    }
+
+    #[test]
+    fn test_check_rate_limit_tracks_each_client_ip_independently() {
+        let mut state = test_state(60, 0);
+        assert!(check_rate_limit(&mut state, "1.1.1.1").is_ok());
+        assert!(check_rate_limit(&mut state, "1.1.1.1").is_err());
+        // A different client IP has its own, untouched budget.
+        assert!(check_rate_limit(&mut state, "2.2.2.2").is_ok());
+    }
+
+    #[test]
+    fn test_check_rate_limit_zero_means_unlimited() {
+        let mut state = test_state(0, 0);
+        for _ in 0..1000 {
+            assert!(check_rate_limit(&mut state, "1.2.3.4").is_ok());
+        }
+    }
+
+    #[test]
+    fn test_prune_rate_limiter_drops_long_idle_entries() {
+        let mut state = test_state(60, 0);
+        let long_idle = Instant::now() - Duration::from_secs(3600);
+        state.rate_limiter.insert("idle-client".to_string(), long_idle);
+        prune_rate_limiter(&mut state);
+        assert!(!state.rate_limiter.contains_key("idle-client"));
+    }
+
+    #[test]
+    fn test_prune_rate_limiter_keeps_entries_within_burst_tolerance() {
+        let mut state = test_state(60, 0);
+        assert!(check_rate_limit(&mut state, "active-client").is_ok());
+        prune_rate_limiter(&mut state);
+        assert!(state.rate_limiter.contains_key("active-client"));
+    }
+
+    #[test]
+    fn test_prune_rate_limiter_is_a_noop_when_unlimited() {
+        let mut state = test_state(0, 0);
+        let long_idle = Instant::now() - Duration::from_secs(3600);
+        state.rate_limiter.insert("idle-client".to_string(), long_idle);
+        prune_rate_limiter(&mut state);
+        assert!(state.rate_limiter.contains_key("idle-client"));
+    }
+
+    #[test]
+    fn test_circuit_breaker_starts_closed_and_admissible() {
+        let breaker = CircuitBreaker::new();
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(breaker.is_admissible());
+    }
+
+    #[test]
+    fn test_circuit_breaker_trips_open_at_the_failure_threshold() {
+        let mut breaker = CircuitBreaker::new();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        breaker.record_failure(now, window, 3);
+        breaker.record_failure(now, window, 3);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record_failure(now, window, 3);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.is_admissible());
+    }
+
+    #[test]
+    fn test_circuit_breaker_does_not_count_failures_outside_the_window() {
+        let mut breaker = CircuitBreaker::new();
+        let t0 = Instant::now();
+        let window = Duration::from_secs(10);
+        breaker.record_failure(t0, window, 2);
+        // The second failure lands well after the first has aged out of the window, so the
+        // breaker should never see two failures "at once" and shouldn't trip.
+        let t1 = t0 + Duration::from_secs(20);
+        breaker.record_failure(t1, window, 2);
+        assert_eq!(breaker.state, BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_circuit_breaker_moves_open_to_half_open_after_cooldown() {
+        let mut breaker = CircuitBreaker::new();
+        let t0 = Instant::now();
+        let cooldown = Duration::from_secs(30);
+        breaker.record_failure(t0, Duration::from_secs(60), 1);
+        assert_eq!(breaker.state, BreakerState::Open);
+        breaker.tick(t0 + Duration::from_secs(10), cooldown);
+        assert_eq!(breaker.state, BreakerState::Open);
+        breaker.tick(t0 + Duration::from_secs(30), cooldown);
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open_admits_exactly_one_probe_at_a_time() {
+        let mut breaker = CircuitBreaker::new();
+        breaker.record_failure(Instant::now(), Duration::from_secs(60), 1);
+        breaker.tick(Instant::now() + Duration::from_secs(60), Duration::from_secs(30));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        assert!(breaker.is_admissible());
+        breaker.claim_probe();
+        assert!(!breaker.is_admissible());
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open_probe_success_closes_the_breaker() {
+        let mut breaker = CircuitBreaker::new();
+        breaker.record_failure(Instant::now(), Duration::from_secs(60), 1);
+        breaker.tick(Instant::now() + Duration::from_secs(60), Duration::from_secs(30));
+        breaker.claim_probe();
+        breaker.record_success();
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(breaker.is_admissible());
+    }
+
+    #[test]
+    fn test_circuit_breaker_half_open_probe_failure_reopens_it() {
+        let mut breaker = CircuitBreaker::new();
+        let t0 = Instant::now();
+        breaker.record_failure(t0, Duration::from_secs(60), 1);
+        breaker.tick(t0 + Duration::from_secs(60), Duration::from_secs(30));
+        breaker.claim_probe();
+        breaker.record_failure(t0 + Duration::from_secs(61), Duration::from_secs(60), 1);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.is_admissible());
+    }
+}
+
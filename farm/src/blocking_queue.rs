@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// A FIFO queue that lets consumers block until an item is available instead of busy-polling
+/// or giving up the moment the queue is momentarily empty.
+///
+/// A producer calls [`BlockingQueue::push`] as items arrive and [`BlockingQueue::close`] once
+/// it's done. Consumers call [`BlockingQueue::pop`] in a loop; it blocks while the queue is
+/// empty and open, and only returns `None` once the queue has been closed and drained.
+pub struct BlockingQueue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> BlockingQueue<T> {
+    pub fn new() -> BlockingQueue<T> {
+        BlockingQueue {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+        }
+    }
+
+    /// Appends an item and wakes one blocked consumer.
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+    }
+
+    /// Blocks while the queue is empty and open. Returns `None` once the queue is closed and
+    /// empty; otherwise returns the next item.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        while state.items.is_empty() && !state.closed {
+            state = self.not_empty.wait(state).unwrap();
+        }
+        state.items.pop_front()
+    }
+
+    /// Marks the queue closed and wakes every consumer blocked in `pop`, so they can notice
+    /// there's nothing left to wait for.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.not_empty.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pop_returns_items_in_fifo_order() {
+        let queue = BlockingQueue::new();
+        queue.push(1);
+        queue.push(2);
+        queue.push(3);
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+    }
+
+    #[test]
+    fn test_pop_returns_none_once_closed_and_drained() {
+        let queue = BlockingQueue::new();
+        queue.push(1);
+        queue.close();
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_pop_blocks_until_an_item_is_pushed() {
+        let queue = Arc::new(BlockingQueue::new());
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.pop())
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.push("hello");
+        assert_eq!(consumer.join().unwrap(), Some("hello"));
+    }
+
+    #[test]
+    fn test_pop_blocks_until_closed_with_an_empty_queue() {
+        let queue: Arc<BlockingQueue<i32>> = Arc::new(BlockingQueue::new());
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.pop())
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), None);
+    }
+}
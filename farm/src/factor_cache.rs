@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Caches the prime factorization of numbers the farm has already factored, so a number that
+/// repeats across a large batch doesn't redo identical trial division. Entries are keyed by the
+/// whole number; numbers that merely share factors are each factored in full.
+///
+/// The common case is a cache hit, so lookups take a shared read lock and many workers can
+/// proceed concurrently; only a miss takes the exclusive write lock to insert the new entry.
+pub struct FactorCache {
+    cache: RwLock<HashMap<u32, Vec<u32>>>,
+}
+
+impl FactorCache {
+    pub fn new() -> FactorCache {
+        FactorCache {
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the cached factorization of `num`, computing and caching it with `compute` on a
+    /// miss.
+    pub fn get_or_compute(&self, num: u32, compute: impl FnOnce(u32) -> Vec<u32>) -> Vec<u32> {
+        if let Some(factors) = self.cache.read().unwrap().get(&num) {
+            return factors.clone();
+        }
+        let factors = compute(num);
+        self.cache
+            .write()
+            .unwrap()
+            .insert(num, factors.clone());
+        factors
+    }
+}
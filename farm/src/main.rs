@@ -1,9 +1,16 @@
+mod blocking_queue;
+mod factor_cache;
+mod thread_pool;
+
+use blocking_queue::BlockingQueue;
+use crossbeam_channel::bounded;
+use factor_cache::FactorCache;
 use std::collections::VecDeque;
-#[allow(unused_imports)]
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::time::Instant;
 #[allow(unused_imports)]
 use std::{env, process, thread};
+use thread_pool::ThreadPool;
 
 /// Determines whether a number is prime. This function is taken from CS 110 factor.py.
 ///
@@ -21,17 +28,13 @@ fn is_prime(num: u32) -> bool {
     true
 }
 
-/// Determines the prime factors of a number and prints them to stdout. This function is taken
-/// from CS 110 factor.py.
-///
-/// You don't need to read or understand this code.
+/// Computes the prime factors of `num` by trial division, or `[num]` if it's prime or 1. This
+/// is the actual factoring work; callers should go through `factor_number`'s `FactorCache` so
+/// repeated numbers don't redo it.
 #[allow(dead_code)]
-fn factor_number(num: u32) {
-    let start = Instant::now();
-
+fn compute_factors(num: u32) -> Vec<u32> {
     if num == 1 || is_prime(num) {
-        println!("{} = {} [time: {:?}]", num, num, start.elapsed());
-        return;
+        return vec![num];
     }
 
     let mut factors = Vec::new();
@@ -43,12 +46,22 @@ fn factor_number(num: u32) {
         }
     }
     factors.sort();
+    factors
+}
+
+/// Determines the prime factors of a number, printing and returning them. Checks `cache` first
+/// so a number that repeats across a batch only gets trial-divided once.
+#[allow(dead_code)]
+fn factor_number(num: u32, cache: &FactorCache) -> Vec<u32> {
+    let start = Instant::now();
+    let factors = cache.get_or_compute(num, compute_factors);
     let factors_str = factors
-        .into_iter()
+        .iter()
         .map(|f| f.to_string())
         .collect::<Vec<String>>()
         .join(" * ");
     println!("{} = {} [time: {:?}]", num, factors_str, start.elapsed());
+    factors
 }
 
 /// Returns a list of numbers supplied via argv.
@@ -66,36 +79,49 @@ fn get_input_numbers() -> VecDeque<u32> {
     numbers
 }
 
-fn get_num_safe(nums: &Arc<Mutex<VecDeque<u32>>>) -> Option<u32> {
-    let mut queue = nums.lock().unwrap();
-    return queue.pop_front();
-}
-
-
-
 fn main() {
     let num_threads = num_cpus::get();
     println!("Farm starting on {} CPUs", num_threads);
     let start = Instant::now();
 
-    // Get numbers to factor and put them in a Arc-Mutex to control access
-    let nums: Arc<Mutex<VecDeque<u32>>> = Arc::new(Mutex::new(get_input_numbers()));
-
-    // TODO: spawn `num_threads` threads, each of which pops numbers off the queue and calls
-    // factor_number() until the queue is empty
-    let mut threads = std::vec::Vec::new();
+    // Workers block on this queue instead of racing a momentarily-empty queue for "done", so a
+    // producer can keep feeding numbers in (e.g. from stdin) while they run.
+    let queue: Arc<BlockingQueue<u32>> = Arc::new(BlockingQueue::new());
+    let cache = Arc::new(FactorCache::new());
+    let pool = ThreadPool::new(num_threads);
+    let (done_sender, done_receiver) = bounded(num_threads);
     for _ in 0..num_threads {
-        let nums_clone = nums.clone();
-        threads.push(thread::spawn(move || {
-            while let Some(n) = get_num_safe(&nums_clone) {
-                factor_number(n);
+        let queue = queue.clone();
+        let cache = cache.clone();
+        let done_sender = done_sender.clone();
+        pool.execute(move || {
+            while let Some(n) = queue.pop() {
+                factor_number(n, &cache);
             }
-        }));
+            done_sender
+                .send(())
+                .expect("main: done receiver unexpectedly closed");
+        });
     }
+    drop(done_sender);
+
+    // Producer: push numbers in as they arrive, then close the queue so idle workers know to
+    // stop waiting.
+    let producer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for n in get_input_numbers() {
+                queue.push(n);
+            }
+            queue.close();
+        })
+    };
+    producer.join().expect("Producer thread panicked.");
 
-    // TODO: join all the threads you created
-    for thread in threads {
-        thread.join().expect("Thread panicked.");
+    for _ in 0..num_threads {
+        done_receiver
+            .recv()
+            .expect("main: a worker closed its done channel early");
     }
 
     println!("Total execution time: {:?}", start.elapsed());
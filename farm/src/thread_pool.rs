@@ -0,0 +1,58 @@
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::thread;
+
+/// A unit of work a `ThreadPool` worker can execute. Boxed so the pool's dispatch channel
+/// doesn't need to know the concrete closure type of every job it will ever run.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed-size pool of long-lived worker threads that can run many successive jobs without
+/// paying thread-creation cost per job.
+///
+/// Jobs are dispatched to workers over a bounded crossbeam channel; each worker loops on
+/// `recv()` until the pool (and every clone of its sender) is dropped.
+pub struct ThreadPool {
+    // `None` only ever observed briefly, while `drop` is closing the channel.
+    job_sender: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Spawns `num_threads` long-lived worker threads, each blocking on the shared job channel
+    /// until a job arrives or the pool is dropped.
+    pub fn new(num_threads: usize) -> ThreadPool {
+        let (job_sender, job_receiver): (Sender<Job>, Receiver<Job>) = bounded(num_threads * 4);
+        let mut workers = Vec::with_capacity(num_threads);
+        for _ in 0..num_threads {
+            let job_receiver = job_receiver.clone();
+            workers.push(thread::spawn(move || {
+                while let Ok(job) = job_receiver.recv() {
+                    job();
+                }
+            }));
+        }
+        ThreadPool {
+            job_sender: Some(job_sender),
+            workers,
+        }
+    }
+
+    /// Runs `job` on whichever worker picks it up next. Does not wait for it to finish.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.job_sender
+            .as_ref()
+            .expect("ThreadPool: execute() called after the pool started shutting down")
+            .send(Box::new(job))
+            .expect("ThreadPool: no live workers to receive job");
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Closes the job channel so every worker's `recv()` loop exits, then joins them so the
+    /// pool doesn't leak threads when it goes out of scope.
+    fn drop(&mut self) {
+        self.job_sender.take();
+        for worker in self.workers.drain(..) {
+            worker.join().expect("ThreadPool: worker thread panicked");
+        }
+    }
+}
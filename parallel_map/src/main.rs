@@ -1,66 +1,174 @@
-use crossbeam_channel::bounded;
+mod thread_pool;
+
+use crossbeam_channel::{bounded, Receiver};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
 use std::{thread, time};
+use thread_pool::ThreadPool;
 
-fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
-where
-    F: FnOnce(T) -> U + Send + Copy + 'static,
-    T: Send + 'static,
-    U: Send + 'static + Default,
-{
-    let mut output_vec: Vec<U> = Vec::with_capacity(input_vec.len());
-    for _ in 0..input_vec.len() {
-        output_vec.push(Default::default());
+/// The panic payload from a job that panicked while mapping, downcast to a message where
+/// possible.
+#[derive(Debug)]
+struct JobPanic {
+    message: String,
+}
+
+impl JobPanic {
+    fn from_payload(payload: Box<dyn Any + Send>) -> JobPanic {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "job panicked with a non-string payload".to_string()
+        };
+        JobPanic { message }
+    }
+}
+
+/// A handle to a batch already dispatched by `map_async`. Unlike `map_blocking`, the calling
+/// thread doesn't have to dedicate itself to draining results: poll with `try_collect` or block
+/// until they're all in with `wait`.
+struct ResultHandle<U> {
+    receiver: Receiver<(usize, Result<U, JobPanic>)>,
+    slots: Vec<Option<Result<U, JobPanic>>>,
+    remaining: usize,
+}
+
+impl<U> ResultHandle<U> {
+    fn new(receiver: Receiver<(usize, Result<U, JobPanic>)>, len: usize) -> ResultHandle<U> {
+        ResultHandle {
+            receiver,
+            slots: (0..len).map(|_| None).collect(),
+            remaining: len,
+        }
+    }
+
+    fn drain_available(&mut self) {
+        while let Ok((index, result)) = self.receiver.try_recv() {
+            self.slots[index] = Some(result);
+            self.remaining -= 1;
+        }
+    }
+
+    /// Non-blocking poll: `Ok(results)` once every job in the batch has reported in, in
+    /// submission order; otherwise hands the handle back in `Err` so the caller can poll again
+    /// later. Consumes `self` so a completed batch can't be collected twice: a second poll after
+    /// an `Ok` would have nothing left in `slots` to collect.
+    #[allow(dead_code)]
+    fn try_collect(mut self) -> Result<Vec<Result<U, JobPanic>>, ResultHandle<U>> {
+        self.drain_available();
+        if self.remaining == 0 {
+            Ok(self
+                .slots
+                .into_iter()
+                .map(|slot| slot.expect("ResultHandle: missing result for index"))
+                .collect())
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Blocks until every job in the batch has reported in, then returns the results in
+    /// submission order.
+    fn wait(mut self) -> Vec<Result<U, JobPanic>> {
+        while self.remaining > 0 {
+            let (index, result) = self
+                .receiver
+                .recv()
+                .expect("ResultHandle: a worker dropped its sender before finishing its job");
+            self.slots[index] = Some(result);
+            self.remaining -= 1;
+        }
+        self.slots
+            .into_iter()
+            .map(|slot| slot.expect("ResultHandle: missing result for index"))
+            .collect()
+    }
+}
+
+/// A mapping operation that can run either synchronously (wait for all results) or
+/// asynchronously (dispatch now, collect later), while preserving input order in both modes.
+trait Mapper<T, U> {
+    /// Dispatches `f` over every element and blocks until all results are back.
+    fn map_blocking(&self, input_vec: Vec<T>) -> Vec<Result<U, JobPanic>>;
+    /// Dispatches `f` over every element and returns immediately with a handle to the
+    /// in-progress results.
+    fn map_async(&self, input_vec: Vec<T>) -> ResultHandle<U>;
+}
+
+/// A `Mapper` backed by a `ThreadPool`, applying the same function `f` to every input.
+struct ParallelMapper<F> {
+    pool: ThreadPool,
+    f: Arc<F>,
+}
+
+impl<F> ParallelMapper<F> {
+    fn new(num_threads: usize, f: F) -> ParallelMapper<F> {
+        ParallelMapper {
+            pool: ThreadPool::new(num_threads),
+            f: Arc::new(f),
+        }
     }
-    // TODO: implement parallel map!
-
-    // This channel will be used to send inputs to the threads to operate on.
-    // Will expect messages of the form (index, input).
-    let (send_to_thread, receive_from_parent) = bounded(input_vec.len());
-    
-    // This channel will be used to send outputs from calling the function back
-    // to the parent. Will expect messages of the form (index, output).
-    let (send_to_parent, receive_from_thread) = bounded(input_vec.len());
-
-    // Spawn all the threads
-    let mut threads = Vec::new();
-    for i in 0..num_threads {
-        let send_to_parent = send_to_parent.clone();
-        let receive_from_parent = receive_from_parent.clone();
-        threads.push(thread::spawn(move || {
-            while let Ok(input_pair) = receive_from_parent.recv() {
-                let (index, input) = input_pair;
-                let output = f(input);
-                let output_pair = (index, output);
-                send_to_parent.send(output_pair).expect("Parent receiver unexpectedly closed!");
-            }
-            drop(send_to_parent);
-        }))
+
+    /// Dispatches one job per input to the pool, catching panics so a bad input can't strand a
+    /// caller waiting on a result that will never arrive.
+    fn dispatch<T, U>(&self, input_vec: Vec<T>) -> ResultHandle<U>
+    where
+        F: Fn(T) -> U + Send + Sync + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let len = input_vec.len();
+        let (send_to_parent, receive_from_thread) = bounded(len);
+        for (index, item) in input_vec.into_iter().enumerate() {
+            let send_to_parent = send_to_parent.clone();
+            let f = self.f.clone();
+            self.pool.execute(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| f(item)))
+                    .map_err(JobPanic::from_payload);
+                send_to_parent
+                    .send((index, result))
+                    .expect("ParallelMapper: parent receiver unexpectedly closed");
+            });
+        }
+        ResultHandle::new(receive_from_thread, len)
     }
-    drop(send_to_parent);
+}
 
-    // Send numbers to the threads, then drop that sender
-    for (index, item) in input_vec.into_iter().enumerate() {
-        let input_pair = (index, item);
-        send_to_thread.send(input_pair).expect("Thread receiver unexpectedly closed!");
+impl<T, U, F> Mapper<T, U> for ParallelMapper<F>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    fn map_blocking(&self, input_vec: Vec<T>) -> Vec<Result<U, JobPanic>> {
+        self.dispatch(input_vec).wait()
     }
-    drop(send_to_thread);
 
-    // Receive results from threads, save to output vector
-    while let Ok(output_pair) = receive_from_thread.recv() {
-        let (index, output) = output_pair;
-        output_vec[index] = output;
+    fn map_async(&self, input_vec: Vec<T>) -> ResultHandle<U> {
+        self.dispatch(input_vec)
     }
-    output_vec
 }
 
 fn main() {
     let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
-    let squares = parallel_map(v, 10, |num| {
+    let mapper = ParallelMapper::new(10, |num| {
         println!("{} squared is {}", num, num * num);
         thread::sleep(time::Duration::from_millis(500));
         num * num
     });
+    let squares = mapper.map_blocking(v);
     println!("squares: {:?}", squares);
+
+    // map_async lets the caller keep working while the batch runs in the background.
+    let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
+    let handle = mapper.map_async(v);
+    println!("dispatched async batch, doing other work in the meantime...");
+    let squares = handle.wait();
+    println!("async squares: {:?}", squares);
+
     let v = vec![6, 7, 8, 9, 10, 1, 2, 3, 4, 5, 12, 18, 11, 5, 20];
     let mut vs = Vec::new();
     for n in v.iter() {